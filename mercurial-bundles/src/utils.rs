@@ -0,0 +1,57 @@
+// Copyright (c) 2017-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Small helpers for decoding fixed- and variable-width fields out of a `BytesMut`.
+
+use byteorder::{BigEndian, ByteOrder};
+use bytes::BytesMut;
+
+use mercurial_types::NodeHash;
+
+use errors::*;
+
+pub trait BytesExt {
+    /// Split off and consume the first `len` bytes as a `NodeHash`.
+    ///
+    /// `len` is the width of the node hashes on the wire (see `wirepack::HashLen`) -- today that's
+    /// always 20 (SHA-1), since `NodeHash` itself has no wider representation yet.
+    fn drain_node(&mut self, len: usize) -> NodeHash;
+
+    fn drain_u16(&mut self) -> u16;
+
+    fn drain_u32(&mut self) -> u32;
+
+    fn drain_u64(&mut self) -> u64;
+
+    /// Split off and consume the first `len` bytes as a raw path.
+    fn drain_path(&mut self, len: usize) -> Result<Vec<u8>>;
+}
+
+impl BytesExt for BytesMut {
+    fn drain_node(&mut self, len: usize) -> NodeHash {
+        let bytes = self.split_to(len);
+        NodeHash::from_bytes(&bytes).expect("node hash has the wrong number of bytes")
+    }
+
+    fn drain_u16(&mut self) -> u16 {
+        let bytes = self.split_to(2);
+        BigEndian::read_u16(&bytes)
+    }
+
+    fn drain_u32(&mut self) -> u32 {
+        let bytes = self.split_to(4);
+        BigEndian::read_u32(&bytes)
+    }
+
+    fn drain_u64(&mut self) -> u64 {
+        let bytes = self.split_to(8);
+        BigEndian::read_u64(&bytes)
+    }
+
+    fn drain_path(&mut self, len: usize) -> Result<Vec<u8>> {
+        Ok(self.split_to(len).to_vec())
+    }
+}
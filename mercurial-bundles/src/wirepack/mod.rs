@@ -4,7 +4,8 @@
 // This software may be used and distributed according to the terms of the
 // GNU General Public License version 2 or any later version.
 
-//! Wire packs. The format is currently undocumented.
+//! Wire packs. The format is mostly undocumented, save for the optional `WirePackVersion::V2`
+//! framing below.
 
 use std::fmt;
 
@@ -17,6 +18,8 @@ use delta;
 use errors::*;
 use utils::BytesExt;
 
+pub mod packer;
+pub mod resolve;
 pub mod unpacker;
 
 /// What sort of wirepack this is.
@@ -37,6 +40,54 @@ impl fmt::Display for Kind {
     }
 }
 
+/// Marker written at the start of a `WirePackVersion::V2` stream, mirroring the way Mercurial's
+/// dirstate-v2 docket begins with a fixed `b"dirstate-v2\n"` signature.
+pub const WIREPACK_V2_MARKER: &[u8] = b"wirepack-v2\n";
+
+/// Bytes reserved immediately after `WIREPACK_V2_MARKER` for future extensions. Today these are
+/// always zero and readers simply skip over them, the same way an unrecognized dirstate-v2
+/// docket extension is ignored rather than rejected.
+pub const WIREPACK_V2_RESERVED_LEN: usize = 8;
+
+/// Which wirepack wire format revision is in play.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum WirePackVersion {
+    /// The original, undocumented format: no marker, and `DataEntry` uses a NULL_HASH delta
+    /// base as a shorthand for "this is a fulltext, not a delta".
+    V1,
+    /// Prefixed with `WIREPACK_V2_MARKER` followed by `WIREPACK_V2_RESERVED_LEN` reserved bytes.
+    /// The v1 NULL_HASH-means-fulltext shortcut is gone: data entries always carry a real
+    /// `Delta`.
+    V2,
+}
+
+impl WirePackVersion {
+    /// Try to read the version marker from the start of `buf`.
+    ///
+    /// Returns `Ok(None)` if there isn't yet enough data to tell one way or the other -- the
+    /// caller should retry once more bytes have arrived. A stream that doesn't begin with
+    /// `WIREPACK_V2_MARKER` is assumed to be the legacy v1 format, and nothing is consumed from
+    /// `buf` in that case, since v1 has no marker to skip over.
+    ///
+    /// Note this only compares against as much of the marker as `buf` currently holds: a v1
+    /// stream diverges from `WIREPACK_V2_MARKER` well before its full length in practice (a v1
+    /// stream opens with a big-endian u16 path length, and `b"wi"` would make that a path of
+    /// over 30,000 bytes), so a short but
+    /// complete v1 pack -- e.g. an empty pack, which is just the 2-byte `End` sentinel -- gets
+    /// identified as v1 without first blocking on 12+ bytes that will never arrive.
+    pub(crate) fn decode(buf: &mut BytesMut) -> Result<Option<Self>> {
+        let have = buf.len().min(WIREPACK_V2_MARKER.len());
+        if buf[..have] != WIREPACK_V2_MARKER[..have] {
+            return Ok(Some(WirePackVersion::V1));
+        }
+        if buf.len() < WIREPACK_V2_MARKER.len() + WIREPACK_V2_RESERVED_LEN {
+            return Ok(None);
+        }
+        buf.split_to(WIREPACK_V2_MARKER.len() + WIREPACK_V2_RESERVED_LEN);
+        Ok(Some(WirePackVersion::V2))
+    }
+}
+
 /// An atomic part returned from the wirepack.
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum Part {
@@ -78,13 +129,73 @@ impl Part {
     }
 }
 
+/// The width, in bytes, of the node hashes embedded in a wirepack entry.
+///
+/// Mercurial's SHA-1 node hashes are 20 bytes. Threading this through explicitly means the decoder computes header offsets for whatever hash
+/// width is actually on the wire, rather than baking in `20` -- which matters because Mercurial's
+/// dirstate-v2 already reserves 32 bytes of node storage in anticipation of a SHA-256 migration,
+/// and wirepacks will eventually need to follow suit.
+///
+/// Only SHA-1 is wired end-to-end today, though: `NodeHash` itself has no wider representation
+/// yet, so `HashLen::new` rejects anything other than `HashLen::SHA1` until `NodeHash` grows one.
+/// The offset math below is already width-aware so that migration doesn't require touching it
+/// again.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct HashLen(usize);
+
+impl HashLen {
+    /// The hash width used by every wirepack in the wild today.
+    pub const SHA1: HashLen = HashLen(20);
+
+    /// Build a `HashLen` for `len` bytes.
+    ///
+    /// Fails unless `len` is a width `NodeHash` can actually represent -- currently just 20
+    /// (SHA-1).
+    pub fn new(len: usize) -> Result<Self> {
+        ensure_err!(
+            len == Self::SHA1.len(),
+            ErrorKind::WirePackDecode(format!(
+                "hash length {} is not supported -- only {}-byte (SHA-1) node hashes are wired \
+                 up end-to-end today",
+                len,
+                Self::SHA1.len(),
+            ))
+        );
+        Ok(HashLen(len))
+    }
+
+    pub fn len(&self) -> usize {
+        self.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+}
+
+impl Default for HashLen {
+    fn default() -> Self {
+        HashLen::SHA1
+    }
+}
+
 // See the history header definition in this file for the breakdown.
-const HISTORY_COPY_FROM_OFFSET: usize = 20 + 20 + 20 + 20;
-const HISTORY_HEADER_SIZE: usize = HISTORY_COPY_FROM_OFFSET + 2;
+fn history_copy_from_offset(hash_len: HashLen) -> usize {
+    hash_len.len() * 4
+}
+
+fn history_header_size(hash_len: HashLen) -> usize {
+    history_copy_from_offset(hash_len) + 2
+}
 
 // See the data header definition in this file for the breakdown.
-const DATA_DELTA_OFFSET: usize = 20 + 20;
-const DATA_HEADER_SIZE: usize = DATA_DELTA_OFFSET + 8;
+fn data_delta_offset(hash_len: HashLen) -> usize {
+    hash_len.len() * 2
+}
+
+fn data_header_size(hash_len: HashLen) -> usize {
+    data_delta_offset(hash_len) + 8
+}
 
 // TODO: move to mercurial-types
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -98,42 +209,54 @@ pub struct HistoryEntry {
 }
 
 impl HistoryEntry {
-    pub(crate) fn decode(buf: &mut BytesMut, kind: Kind) -> Result<Option<Self>> {
-        if buf.len() < HISTORY_HEADER_SIZE {
+    pub(crate) fn decode(buf: &mut BytesMut, kind: Kind, hash_len: HashLen) -> Result<Option<Self>> {
+        let header_size = history_header_size(hash_len);
+        if buf.len() < header_size {
             return Ok(None);
         }
 
         // A history revision has:
         // ---
-        // node: NodeHash (20 bytes)
-        // p1: NodeHash (20 bytes)
-        // p2: NodeHash (20 bytes)
-        // link node: NodeHash (20 bytes)
+        // node: NodeHash (hash_len bytes)
+        // p1: NodeHash (hash_len bytes)
+        // p2: NodeHash (hash_len bytes)
+        // link node: NodeHash (hash_len bytes)
         // copy from len: u16 (2 bytes) -- 0 if this revision is not a copy
         // copy from: RepoPath (<copy from len> bytes)
         // ---
         // Tree revisions are never copied, so <copy from len> is always 0.
 
+        // Peek the variable-length field from a borrowed view of `buf` -- nothing is consumed
+        // yet, so a partial read leaves `buf` untouched and the caller can safely retry once
+        // more bytes arrive.
+        let copy_from_offset = history_copy_from_offset(hash_len);
         let copy_from_len =
-            BigEndian::read_u16(&buf[HISTORY_COPY_FROM_OFFSET..HISTORY_HEADER_SIZE]) as usize;
-        if buf.len() < HISTORY_HEADER_SIZE + copy_from_len {
+            BigEndian::read_u16(&buf[copy_from_offset..header_size]) as usize;
+        let total_len = header_size + copy_from_len;
+        if buf.len() < total_len {
             return Ok(None);
         }
 
-        let node = buf.drain_node();
-        let p1 = buf.drain_node();
-        let p2 = buf.drain_node();
-        let linknode = buf.drain_node();
-        let _ = buf.drain_u16();
+        // The whole entry is confirmed present, so this is the only place `buf` is mutated.
+        // `split_to` just hands back a reference-counted view into the same underlying storage,
+        // so isolating the entry this way doesn't copy its bytes.
+        let mut entry = buf.split_to(total_len);
+
+        let node = entry.drain_node(hash_len.len());
+        let p1 = entry.drain_node(hash_len.len());
+        let p2 = entry.drain_node(hash_len.len());
+        let linknode = entry.drain_node(hash_len.len());
+        let _ = entry.drain_u16();
         let copy_from = if copy_from_len > 0 {
-            let path = buf.drain_path(copy_from_len)?;
+            // Another zero-copy sub-slice, this time of the path itself.
+            let path = entry.split_to(copy_from_len);
             match kind {
                 Kind::Tree => bail_err!(ErrorKind::WirePackDecode(format!(
                     "tree entry {} is marked as copied from path {}, but they cannot be copied",
                     node,
-                    path
+                    String::from_utf8_lossy(&path)
                 ))),
-                Kind::File => Some(RepoPath::file(path).with_context(|_| {
+                Kind::File => Some(RepoPath::file(path.to_vec()).with_context(|_| {
                     ErrorKind::WirePackDecode("invalid copy from path".into())
                 })?),
             }
@@ -199,32 +322,45 @@ pub struct DataEntry {
 }
 
 impl DataEntry {
-    pub(crate) fn decode(buf: &mut BytesMut) -> Result<Option<Self>> {
-        if buf.len() < DATA_HEADER_SIZE {
+    pub(crate) fn decode(
+        buf: &mut BytesMut,
+        hash_len: HashLen,
+        version: WirePackVersion,
+    ) -> Result<Option<Self>> {
+        let header_size = data_header_size(hash_len);
+        if buf.len() < header_size {
             return Ok(None);
         }
 
         // A data revision has:
         // ---
-        // node: NodeHash (20 bytes)
-        // delta base: NodeHash (20 bytes) -- NULL_HASH if full text
+        // node: NodeHash (hash_len bytes)
+        // delta base: NodeHash (hash_len bytes) -- NULL_HASH if full text (v1 only)
         // delta len: u64 (8 bytes)
         // delta: Delta (<delta len> bytes)
         // ---
-        // There's a bit of a wart in the current format: if delta base is NULL_HASH, instead of
-        // storing a delta with start = 0 and end = 0, we store the full text directly. This
-        // should be fixed in a future wire protocol revision.
-        let delta_len = BigEndian::read_u64(&buf[DATA_DELTA_OFFSET..DATA_HEADER_SIZE]) as usize;
-        if buf.len() < DATA_HEADER_SIZE + delta_len {
+        // There's a bit of a wart in the v1 format: if delta base is NULL_HASH, instead of
+        // storing a delta with start = 0 and end = 0, we store the full text directly. v2 drops
+        // this shortcut -- every entry carries a real `Delta`.
+        // As with `HistoryEntry::decode`, peek the variable-length field from a borrowed view of
+        // `buf` before deciding whether to consume anything.
+        let delta_offset = data_delta_offset(hash_len);
+        let delta_len = BigEndian::read_u64(&buf[delta_offset..header_size]) as usize;
+        let total_len = header_size + delta_len;
+        if buf.len() < total_len {
             return Ok(None);
         }
 
-        let node = buf.drain_node();
-        let delta_base = buf.drain_node();
-        let _ = buf.drain_u64();
-        let delta = buf.split_to(delta_len);
+        // The whole entry is confirmed present, so this is the only place `buf` is mutated.
+        let mut entry = buf.split_to(total_len);
+
+        let node = entry.drain_node(hash_len.len());
+        let delta_base = entry.drain_node(hash_len.len());
+        let _ = entry.drain_u64();
+        // A zero-copy sub-slice of the original network buffer, not a fresh allocation.
+        let delta = entry.split_to(delta_len);
 
-        let delta = if delta_base == NULL_HASH {
+        let delta = if version == WirePackVersion::V1 && delta_base == NULL_HASH {
             Delta::new_fulltext(delta.to_vec())
         } else {
             delta::decode_delta(delta)?
@@ -280,4 +416,78 @@ mod test {
             copy_from,
         }
     }
+
+    #[test]
+    fn test_hash_len_rejects_unsupported_widths() {
+        HashLen::new(20).expect("20-byte (SHA-1) hashes are supported");
+        HashLen::new(32).expect_err("32-byte hashes aren't wired up to NodeHash yet");
+        HashLen::new(0).expect_err("a zero-length hash isn't valid");
+    }
+
+    #[test]
+    fn test_history_decode_with_explicit_hash_len() {
+        // Exercise the hash-length parameter via an explicit (non-`Default`) construction, to
+        // make sure it's actually threaded through `decode` rather than just accepted and
+        // ignored.
+        let hash_len = HashLen::new(20).unwrap();
+
+        let mut buf = BytesMut::from(vec![0u8; 20 * 4 + 2]);
+        let entry = HistoryEntry::decode(&mut buf, Kind::File, hash_len)
+            .expect("decode should succeed")
+            .expect("a full entry should be present");
+
+        assert_eq!(entry.node, NULL_HASH);
+        assert_eq!(entry.copy_from, None);
+        assert!(buf.is_empty(), "the whole entry should have been consumed");
+    }
+
+    #[test]
+    fn test_history_decode_backpressure_on_partial_buffer() {
+        // A full v1 HistoryEntry header with no copy-from path: four 20-byte hashes and a
+        // 2-byte copy-from length of 0.
+        let full = BytesMut::from(vec![0u8; 20 * 4 + 2]);
+
+        let mut truncated = full.clone();
+        truncated.truncate(full.len() - 1);
+        let truncated_len = truncated.len();
+
+        let result = HistoryEntry::decode(&mut truncated, Kind::File, HashLen::SHA1)
+            .expect("decode should not error on a partial buffer");
+        assert!(result.is_none(), "a truncated entry should not decode yet");
+        assert_eq!(
+            truncated.len(),
+            truncated_len,
+            "a partial read must not consume any bytes from the buffer"
+        );
+
+        let mut complete = full;
+        HistoryEntry::decode(&mut complete, Kind::File, HashLen::SHA1)
+            .expect("decode should succeed")
+            .expect("the full entry should decode once complete");
+    }
+
+    #[test]
+    fn test_data_decode_backpressure_on_partial_buffer() {
+        // A full v1 DataEntry header for a fulltext entry (delta_base == NULL_HASH) with an
+        // empty payload: two 20-byte hashes and an 8-byte delta length of 0.
+        let full = BytesMut::from(vec![0u8; 20 * 2 + 8]);
+
+        let mut truncated = full.clone();
+        truncated.truncate(full.len() - 1);
+        let truncated_len = truncated.len();
+
+        let result = DataEntry::decode(&mut truncated, HashLen::SHA1, WirePackVersion::V1)
+            .expect("decode should not error on a partial buffer");
+        assert!(result.is_none(), "a truncated entry should not decode yet");
+        assert_eq!(
+            truncated.len(),
+            truncated_len,
+            "a partial read must not consume any bytes from the buffer"
+        );
+
+        let mut complete = full;
+        DataEntry::decode(&mut complete, HashLen::SHA1, WirePackVersion::V1)
+            .expect("decode should succeed")
+            .expect("the full entry should decode once complete");
+    }
 }
@@ -0,0 +1,195 @@
+// Copyright (c) 2017-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Encoder for wire packs -- the write-side counterpart to `unpacker`.
+
+use bytes::{BufMut, Bytes, BytesMut};
+
+use mercurial_types::{RepoPath, NULL_HASH};
+
+use delta;
+use errors::*;
+
+use super::{
+    DataEntry, HistoryEntry, Kind, Part, WirePackVersion, WIREPACK_V2_MARKER,
+    WIREPACK_V2_RESERVED_LEN,
+};
+
+/// Turns a stream of `Part`s into correctly framed wirepack bytes.
+///
+/// Callers are expected to feed in `Part`s in the same order an unpacker would yield them for a
+/// valid pack -- `HistoryMeta`, then that many `History` entries, then `DataMeta`, then that many
+/// `Data` entries, repeated once per path, and finished off with a single `End`. `Kind` governs
+/// which invariants `HistoryEntry::verify` enforces (in particular, whether copies are allowed).
+pub struct WirePackPacker {
+    kind: Kind,
+    version: WirePackVersion,
+    wrote_marker: bool,
+}
+
+impl WirePackPacker {
+    /// A packer producing the legacy v1 format (no marker, NULL_HASH fulltext shortcut).
+    pub fn new(kind: Kind) -> Self {
+        Self::new_with_version(kind, WirePackVersion::V1)
+    }
+
+    pub fn new_with_version(kind: Kind, version: WirePackVersion) -> Self {
+        Self {
+            kind,
+            version,
+            // v1 has no marker to write, so pretend it's already been written.
+            wrote_marker: version == WirePackVersion::V1,
+        }
+    }
+
+    /// Encode a single `Part`, appending its wire bytes to `out`.
+    pub fn encode(&mut self, part: &Part, out: &mut BytesMut) -> Result<()> {
+        if !self.wrote_marker {
+            out.reserve(WIREPACK_V2_MARKER.len() + WIREPACK_V2_RESERVED_LEN);
+            out.put_slice(WIREPACK_V2_MARKER);
+            out.put_slice(&[0u8; WIREPACK_V2_RESERVED_LEN]);
+            self.wrote_marker = true;
+        }
+
+        match *part {
+            Part::HistoryMeta {
+                ref path,
+                entry_count,
+            } => self.encode_meta(path, entry_count, out),
+            Part::History(ref entry) => self.encode_history(entry, out),
+            Part::DataMeta {
+                ref path,
+                entry_count,
+            } => self.encode_meta(path, entry_count, out),
+            Part::Data(ref entry) => self.encode_data(entry, out),
+            Part::End => {
+                // A path length of 0 in place of the next `HistoryMeta`/`DataMeta` header marks
+                // the end of the pack.
+                out.reserve(2);
+                out.put_u16_be(0);
+                Ok(())
+            }
+        }
+    }
+
+    fn encode_meta(&mut self, path: &RepoPath, entry_count: usize, out: &mut BytesMut) -> Result<()> {
+        let path = path_bytes(path)?;
+        ensure_err!(
+            path.len() <= (u16::max_value() as usize),
+            ErrorKind::WirePackEncode(format!(
+                "path {:?} is longer than the maximum supported length {}",
+                path,
+                u16::max_value(),
+            ))
+        );
+
+        out.reserve(2 + path.len() + 4);
+        out.put_u16_be(path.len() as u16);
+        out.put_slice(&path);
+        out.put_u32_be(entry_count as u32);
+        Ok(())
+    }
+
+    fn encode_history(&mut self, entry: &HistoryEntry, out: &mut BytesMut) -> Result<()> {
+        entry.verify(self.kind)?;
+
+        let copy_from = match entry.copy_from {
+            Some(ref path) => path_bytes(path)?,
+            None => Vec::new(),
+        };
+
+        out.reserve(4 * entry.node.as_bytes().len() + 2 + copy_from.len());
+        out.put_slice(entry.node.as_bytes());
+        out.put_slice(entry.p1.as_bytes());
+        out.put_slice(entry.p2.as_bytes());
+        out.put_slice(entry.linknode.as_bytes());
+        out.put_u16_be(copy_from.len() as u16);
+        out.put_slice(&copy_from);
+        Ok(())
+    }
+
+    fn encode_data(&mut self, entry: &DataEntry, out: &mut BytesMut) -> Result<()> {
+        // v1 mirrors the fulltext shortcut `DataEntry::decode` understands: when the delta base
+        // is NULL_HASH, the payload is the fulltext itself rather than an encoded `Delta`. v2
+        // dropped the shortcut, so it always writes a real encoded `Delta`.
+        let payload: Bytes = if self.version == WirePackVersion::V1 && entry.delta_base == NULL_HASH
+        {
+            match entry.delta.maybe_fulltext() {
+                Some(fulltext) => Bytes::from(fulltext),
+                None => bail_err!(ErrorKind::WirePackEncode(format!(
+                    "data entry {} has a NULL_HASH delta base but is not a fulltext",
+                    entry.node
+                ))),
+            }
+        } else {
+            delta::encode_delta(&entry.delta)
+        };
+
+        out.reserve(2 * entry.node.as_bytes().len() + 8 + payload.len());
+        out.put_slice(entry.node.as_bytes());
+        out.put_slice(entry.delta_base.as_bytes());
+        out.put_u64_be(payload.len() as u64);
+        out.put_slice(&payload);
+        Ok(())
+    }
+}
+
+fn path_bytes(path: &RepoPath) -> Result<Vec<u8>> {
+    match *path {
+        RepoPath::RootPath => bail_err!(ErrorKind::WirePackEncode(
+            "the root path cannot appear in a wirepack entry".into()
+        )),
+        RepoPath::DirectoryPath(ref path) => Ok(path.to_vec()),
+        RepoPath::FilePath(ref path) => Ok(path.to_vec()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::BytesMut;
+
+    use mercurial_types::{Delta, RepoPath, NULL_HASH};
+
+    use super::*;
+
+    #[test]
+    fn test_encode_history_enforces_verify() {
+        let mut packer = WirePackPacker::new(Kind::Tree);
+        let mut out = BytesMut::new();
+
+        // A tree entry can't be marked as copied -- `HistoryEntry::verify` rejects this, and
+        // `encode_history` must propagate that rather than silently writing bad bytes.
+        let entry = HistoryEntry {
+            node: NULL_HASH,
+            p1: NULL_HASH,
+            p2: NULL_HASH,
+            linknode: NULL_HASH,
+            copy_from: Some(RepoPath::file("bar").unwrap()),
+        };
+
+        packer
+            .encode(&Part::History(entry), &mut out)
+            .expect_err("a copied tree entry should fail to encode");
+    }
+
+    #[test]
+    fn test_encode_data_fulltext_shortcut() {
+        let mut packer = WirePackPacker::new(Kind::File);
+        let mut out = BytesMut::new();
+
+        let entry = DataEntry {
+            node: NULL_HASH,
+            delta_base: NULL_HASH,
+            delta: Delta::new_fulltext(b"hello".to_vec()),
+        };
+
+        packer
+            .encode(&Part::Data(entry), &mut out)
+            .expect("a v1 fulltext data entry should encode");
+        // node (20) + delta_base (20) + delta len (8) + payload (5)
+        assert_eq!(out.len(), 20 + 20 + 8 + 5);
+    }
+}
@@ -0,0 +1,299 @@
+// Copyright (c) 2017-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Resolve a path's `Data` entries into per-node fulltexts by walking delta chains.
+
+use std::collections::HashMap;
+
+use mercurial_types::{Delta, NodeHash, NULL_HASH};
+
+use errors::*;
+
+use super::{DataEntry, WirePackVersion};
+
+/// Materializes fulltexts for an ordered stream of `DataEntry`s that all share a single path.
+///
+/// A wirepack sends data as a chain of deltas rather than full revlog-style storage, so there's
+/// no index to consult the way a revlog has -- resolving a node's content means walking the
+/// chain of deltas that lead to it. This keeps the fulltext of every node seen so far around so
+/// that a later entry can apply its delta against whichever one it names as `delta_base`.
+pub struct DeltaChainResolver {
+    version: WirePackVersion,
+    fulltexts: HashMap<NodeHash, Vec<u8>>,
+}
+
+impl DeltaChainResolver {
+    /// `version` must match whatever was negotiated for the stream these entries came from: a
+    /// `NULL_HASH` delta base means "this is a fulltext" in v1, but in v2 (which dropped that
+    /// shortcut) it just means "there's no base", and the entry still carries a real `Delta` to
+    /// apply.
+    pub fn new(version: WirePackVersion) -> Self {
+        Self {
+            version,
+            fulltexts: HashMap::new(),
+        }
+    }
+
+    /// Apply `entry` on top of whatever's already been resolved, returning the fulltext for its
+    /// `node`. Entries for a given path must be fed in the order they were received on the wire,
+    /// since a delta's base must have been resolved already.
+    pub fn resolve(&mut self, entry: &DataEntry) -> Result<Vec<u8>> {
+        let fulltext = if entry.delta_base == NULL_HASH {
+            match (self.version, entry.delta.maybe_fulltext()) {
+                (WirePackVersion::V1, Some(fulltext)) => fulltext.to_vec(),
+                (WirePackVersion::V1, None) => bail_err!(ErrorKind::WirePackDecode(format!(
+                    "data entry {} has a NULL_HASH delta base but is not a fulltext",
+                    entry.node
+                ))),
+                // v2 dropped the fulltext shortcut: NULL_HASH here just means "no base", and the
+                // delta -- a real one, same as any other entry -- applies against an empty base.
+                (WirePackVersion::V2, _) => apply_delta(&[], &entry.delta, entry.node)?,
+            }
+        } else {
+            let base = self.fulltexts.get(&entry.delta_base).ok_or_else(|| {
+                ErrorKind::WirePackDecode(format!(
+                    "data entry {} has delta base {}, which hasn't been seen yet -- the delta \
+                     chain is out of order",
+                    entry.node, entry.delta_base
+                ))
+            })?;
+            apply_delta(base, &entry.delta, entry.node)?
+        };
+
+        self.fulltexts.insert(entry.node, fulltext.clone());
+        Ok(fulltext)
+    }
+}
+
+/// Apply `delta` to `base`, producing the resulting fulltext.
+fn apply_delta(base: &[u8], delta: &Delta, node: NodeHash) -> Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(base.len());
+    let mut last_end = 0usize;
+
+    for chunk in &delta.frags {
+        ensure_err!(
+            chunk.start >= last_end,
+            ErrorKind::WirePackDecode(format!(
+                "data entry {} has a delta chunk starting at {}, before the previous chunk's \
+                 end {}",
+                node, chunk.start, last_end
+            ))
+        );
+        ensure_err!(
+            chunk.start <= chunk.end,
+            ErrorKind::WirePackDecode(format!(
+                "data entry {} has a delta chunk starting at {}, after its own end {}",
+                node, chunk.start, chunk.end
+            ))
+        );
+        ensure_err!(
+            chunk.end <= base.len(),
+            ErrorKind::WirePackDecode(format!(
+                "data entry {} has a delta chunk ending at {}, past the base's length {}",
+                node,
+                chunk.end,
+                base.len()
+            ))
+        );
+
+        out.extend_from_slice(&base[last_end..chunk.start]);
+        out.extend_from_slice(&chunk.content);
+        last_end = chunk.end;
+    }
+
+    out.extend_from_slice(&base[last_end..]);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod test {
+    use mercurial_types::{Fragment, NULL_HASH};
+
+    use super::*;
+
+    fn node(byte: u8) -> NodeHash {
+        NodeHash::from_bytes(&[byte; 20]).unwrap()
+    }
+
+    #[test]
+    fn test_resolve_v1_fulltext() {
+        let mut resolver = DeltaChainResolver::new(WirePackVersion::V1);
+        let entry = DataEntry {
+            node: node(1),
+            delta_base: NULL_HASH,
+            delta: Delta::new_fulltext(b"hello".to_vec()),
+        };
+
+        let fulltext = resolver.resolve(&entry).expect("fulltext should resolve");
+        assert_eq!(fulltext, b"hello");
+    }
+
+    #[test]
+    fn test_resolve_v2_null_hash_base_is_not_a_fulltext_shortcut() {
+        // v2 dropped the fulltext shortcut, so a NULL_HASH delta base just means "no base" -- the
+        // delta is real and applies against an empty base.
+        let mut resolver = DeltaChainResolver::new(WirePackVersion::V2);
+        let entry = DataEntry {
+            node: node(1),
+            delta_base: NULL_HASH,
+            delta: Delta {
+                frags: vec![Fragment {
+                    start: 0,
+                    end: 0,
+                    content: b"hello".to_vec(),
+                }],
+            },
+        };
+
+        let fulltext = resolver.resolve(&entry).expect("v2 delta should resolve");
+        assert_eq!(fulltext, b"hello");
+    }
+
+    #[test]
+    fn test_resolve_multi_chunk_delta() {
+        let mut resolver = DeltaChainResolver::new(WirePackVersion::V1);
+        let base_entry = DataEntry {
+            node: node(1),
+            delta_base: NULL_HASH,
+            delta: Delta::new_fulltext(b"hello world".to_vec()),
+        };
+        resolver.resolve(&base_entry).expect("base should resolve");
+
+        let patch_entry = DataEntry {
+            node: node(2),
+            delta_base: node(1),
+            delta: Delta {
+                frags: vec![
+                    Fragment {
+                        start: 0,
+                        end: 5,
+                        content: b"goodbye".to_vec(),
+                    },
+                    Fragment {
+                        start: 6,
+                        end: 11,
+                        content: b"earth".to_vec(),
+                    },
+                ],
+            },
+        };
+
+        let fulltext = resolver
+            .resolve(&patch_entry)
+            .expect("patch should resolve against its base");
+        assert_eq!(fulltext, b"goodbye earth");
+    }
+
+    #[test]
+    fn test_resolve_out_of_order_delta_base_errors() {
+        let mut resolver = DeltaChainResolver::new(WirePackVersion::V1);
+        let entry = DataEntry {
+            node: node(2),
+            delta_base: node(1),
+            delta: Delta {
+                frags: vec![Fragment {
+                    start: 0,
+                    end: 0,
+                    content: b"hello".to_vec(),
+                }],
+            },
+        };
+
+        resolver
+            .resolve(&entry)
+            .expect_err("a delta base that hasn't been resolved yet should error");
+    }
+
+    #[test]
+    fn test_resolve_delta_chunk_past_base_end_errors() {
+        let mut resolver = DeltaChainResolver::new(WirePackVersion::V1);
+        let base_entry = DataEntry {
+            node: node(1),
+            delta_base: NULL_HASH,
+            delta: Delta::new_fulltext(b"hi".to_vec()),
+        };
+        resolver.resolve(&base_entry).expect("base should resolve");
+
+        let patch_entry = DataEntry {
+            node: node(2),
+            delta_base: node(1),
+            delta: Delta {
+                frags: vec![Fragment {
+                    start: 0,
+                    end: 100,
+                    content: b"too long".to_vec(),
+                }],
+            },
+        };
+
+        resolver
+            .resolve(&patch_entry)
+            .expect_err("a delta chunk ending past the base's length should error");
+    }
+
+    #[test]
+    fn test_resolve_overlapping_chunks_errors() {
+        let mut resolver = DeltaChainResolver::new(WirePackVersion::V1);
+        let base_entry = DataEntry {
+            node: node(1),
+            delta_base: NULL_HASH,
+            delta: Delta::new_fulltext(b"hello world".to_vec()),
+        };
+        resolver.resolve(&base_entry).expect("base should resolve");
+
+        let patch_entry = DataEntry {
+            node: node(2),
+            delta_base: node(1),
+            delta: Delta {
+                frags: vec![
+                    Fragment {
+                        start: 0,
+                        end: 5,
+                        content: b"goodbye".to_vec(),
+                    },
+                    Fragment {
+                        start: 3,
+                        end: 8,
+                        content: b"earth".to_vec(),
+                    },
+                ],
+            },
+        };
+
+        resolver
+            .resolve(&patch_entry)
+            .expect_err("a chunk starting before the previous chunk's end should error");
+    }
+
+    #[test]
+    fn test_resolve_inverted_chunk_errors() {
+        let mut resolver = DeltaChainResolver::new(WirePackVersion::V1);
+        let base_entry = DataEntry {
+            node: node(1),
+            delta_base: NULL_HASH,
+            delta: Delta::new_fulltext(b"hi there".to_vec()),
+        };
+        resolver.resolve(&base_entry).expect("base should resolve");
+
+        // An inverted chunk (start past its own end) is non-monotonic even though it happens to
+        // satisfy `start >= last_end` and `end <= base.len()` on their own.
+        let patch_entry = DataEntry {
+            node: node(2),
+            delta_base: node(1),
+            delta: Delta {
+                frags: vec![Fragment {
+                    start: 7,
+                    end: 5,
+                    content: b"x".to_vec(),
+                }],
+            },
+        };
+
+        resolver
+            .resolve(&patch_entry)
+            .expect_err("a delta chunk starting after its own end should error");
+    }
+}
@@ -0,0 +1,304 @@
+// Copyright (c) 2017-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Decoder for wire packs -- the read-side counterpart to `packer`.
+
+use byteorder::{BigEndian, ByteOrder};
+use bytes::BytesMut;
+
+use mercurial_types::RepoPath;
+
+use errors::*;
+use utils::BytesExt;
+
+use super::{
+    DataEntry, HashLen, HistoryEntry, Kind, Part, WirePackVersion,
+};
+
+/// Where a `WirePackUnpacker` is within the current file's history/data sections.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum State {
+    ExpectHistoryMeta,
+    ExpectHistory { remaining: usize },
+    ExpectDataMeta,
+    ExpectData { remaining: usize },
+    Done,
+}
+
+enum MetaResult {
+    Meta(RepoPath, usize),
+    End,
+}
+
+/// Streaming decoder for a wirepack, handing back one `Part` at a time.
+///
+/// Call `decode_next` with the same `BytesMut` the network keeps appending to; it returns
+/// `Ok(None)` without consuming any of a `Part` when the buffer doesn't yet hold all of it, so
+/// callers can safely retry once more bytes have arrived. The wirepack version is negotiated
+/// from the stream's leading marker (see `WirePackVersion`) the first time `decode_next` is
+/// called with enough bytes to tell, and is available afterwards via `version`. A complete v2
+/// marker is consumed at that point, even if the first `Part` after it is still incomplete.
+pub struct WirePackUnpacker {
+    kind: Kind,
+    hash_len: HashLen,
+    version: WirePackVersion,
+    negotiated: bool,
+    state: State,
+}
+
+impl WirePackUnpacker {
+    pub fn new(kind: Kind) -> Self {
+        Self::new_with_hash_len(kind, HashLen::SHA1)
+    }
+
+    pub fn new_with_hash_len(kind: Kind, hash_len: HashLen) -> Self {
+        Self {
+            kind,
+            hash_len,
+            version: WirePackVersion::V1,
+            negotiated: false,
+            state: State::ExpectHistoryMeta,
+        }
+    }
+
+    /// The wirepack version negotiated from the stream's leading marker.
+    ///
+    /// Defaults to `WirePackVersion::V1` until `decode_next` has seen enough of the stream to
+    /// read its leading marker -- a stream that never gets that far never proves it's anything
+    /// other than legacy v1.
+    pub fn version(&self) -> WirePackVersion {
+        self.version
+    }
+
+    /// Decode the next `Part` out of `buf`, if one is fully present.
+    pub fn decode_next(&mut self, buf: &mut BytesMut) -> Result<Option<Part>> {
+        if !self.negotiated {
+            match WirePackVersion::decode(buf)? {
+                None => return Ok(None),
+                Some(version) => {
+                    self.version = version;
+                    self.negotiated = true;
+                }
+            }
+        }
+
+        match self.state {
+            State::ExpectHistoryMeta => match decode_meta(buf, self.kind)? {
+                None => Ok(None),
+                Some(MetaResult::End) => {
+                    self.state = State::Done;
+                    Ok(Some(Part::End))
+                }
+                Some(MetaResult::Meta(path, entry_count)) => {
+                    self.state = if entry_count == 0 {
+                        State::ExpectDataMeta
+                    } else {
+                        State::ExpectHistory {
+                            remaining: entry_count,
+                        }
+                    };
+                    Ok(Some(Part::HistoryMeta { path, entry_count }))
+                }
+            },
+            State::ExpectHistory { remaining } => {
+                match HistoryEntry::decode(buf, self.kind, self.hash_len)? {
+                    None => Ok(None),
+                    Some(entry) => {
+                        self.state = if remaining > 1 {
+                            State::ExpectHistory {
+                                remaining: remaining - 1,
+                            }
+                        } else {
+                            State::ExpectDataMeta
+                        };
+                        Ok(Some(Part::History(entry)))
+                    }
+                }
+            }
+            State::ExpectDataMeta => match decode_meta(buf, self.kind)? {
+                None => Ok(None),
+                Some(MetaResult::End) => {
+                    self.state = State::Done;
+                    Ok(Some(Part::End))
+                }
+                Some(MetaResult::Meta(path, entry_count)) => {
+                    self.state = if entry_count == 0 {
+                        State::ExpectHistoryMeta
+                    } else {
+                        State::ExpectData {
+                            remaining: entry_count,
+                        }
+                    };
+                    Ok(Some(Part::DataMeta { path, entry_count }))
+                }
+            },
+            State::ExpectData { remaining } => {
+                match DataEntry::decode(buf, self.hash_len, self.version)? {
+                    None => Ok(None),
+                    Some(entry) => {
+                        self.state = if remaining > 1 {
+                            State::ExpectData {
+                                remaining: remaining - 1,
+                            }
+                        } else {
+                            State::ExpectHistoryMeta
+                        };
+                        Ok(Some(Part::Data(entry)))
+                    }
+                }
+            }
+            State::Done => Ok(None),
+        }
+    }
+}
+
+/// Decode the `HistoryMeta`/`DataMeta` header shared by both sections: a path length, the path
+/// itself, and how many entries follow. A path length of 0 marks the end of the whole pack.
+fn decode_meta(buf: &mut BytesMut, kind: Kind) -> Result<Option<MetaResult>> {
+    if buf.len() < 2 {
+        return Ok(None);
+    }
+    let path_len = BigEndian::read_u16(&buf[0..2]) as usize;
+    if path_len == 0 {
+        let _ = buf.drain_u16();
+        return Ok(Some(MetaResult::End));
+    }
+
+    if buf.len() < 2 + path_len + 4 {
+        return Ok(None);
+    }
+
+    let _ = buf.drain_u16();
+    let path = buf.drain_path(path_len)?;
+    let entry_count = buf.drain_u32() as usize;
+
+    let path = match kind {
+        Kind::File => RepoPath::file(path)
+            .with_context(|_| ErrorKind::WirePackDecode("invalid meta path".into()))?,
+        Kind::Tree => RepoPath::dir(path)
+            .with_context(|_| ErrorKind::WirePackDecode("invalid meta path".into()))?,
+    };
+
+    Ok(Some(MetaResult::Meta(path, entry_count)))
+}
+
+#[cfg(test)]
+mod test {
+    use mercurial_types::{Delta, NULL_HASH};
+
+    use super::*;
+    use wirepack::packer::WirePackPacker;
+
+    fn drain_all(unpacker: &mut WirePackUnpacker, buf: &mut BytesMut) -> Vec<Part> {
+        let mut parts = Vec::new();
+        loop {
+            match unpacker.decode_next(buf).expect("decode should not error") {
+                Some(Part::End) => {
+                    parts.push(Part::End);
+                    break;
+                }
+                Some(part) => parts.push(part),
+                None => panic!("decode_next returned Ok(None) on a complete buffer"),
+            }
+        }
+        parts
+    }
+
+    #[test]
+    fn test_empty_pack_round_trips_v1() {
+        // This is the regression case: an empty v1 pack is just the 2-byte `End` sentinel, far
+        // shorter than `WIREPACK_V2_MARKER`, and must not get stuck waiting for marker-length
+        // bytes that will never come.
+        let mut packer = WirePackPacker::new(Kind::File);
+        let mut buf = BytesMut::new();
+        packer.encode(&Part::End, &mut buf).unwrap();
+
+        let mut unpacker = WirePackUnpacker::new(Kind::File);
+        let parts = drain_all(&mut unpacker, &mut buf);
+
+        assert_eq!(parts, vec![Part::End]);
+        assert_eq!(unpacker.version(), WirePackVersion::V1);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_round_trips_v1_with_entries() {
+        let path = RepoPath::file("foo").unwrap();
+        let history = HistoryEntry {
+            node: NULL_HASH,
+            p1: NULL_HASH,
+            p2: NULL_HASH,
+            linknode: NULL_HASH,
+            copy_from: None,
+        };
+        let data = DataEntry {
+            node: NULL_HASH,
+            delta_base: NULL_HASH,
+            delta: Delta::new_fulltext(b"hello".to_vec()),
+        };
+
+        let parts_in = vec![
+            Part::HistoryMeta {
+                path: path.clone(),
+                entry_count: 1,
+            },
+            Part::History(history.clone()),
+            Part::DataMeta {
+                path: path.clone(),
+                entry_count: 1,
+            },
+            Part::Data(data.clone()),
+            Part::End,
+        ];
+
+        let mut packer = WirePackPacker::new(Kind::File);
+        let mut buf = BytesMut::new();
+        for part in &parts_in {
+            packer.encode(part, &mut buf).unwrap();
+        }
+
+        let mut unpacker = WirePackUnpacker::new(Kind::File);
+        let parts_out = drain_all(&mut unpacker, &mut buf);
+
+        assert_eq!(parts_out, parts_in);
+        assert_eq!(unpacker.version(), WirePackVersion::V1);
+    }
+
+    #[test]
+    fn test_round_trips_v2_with_entries() {
+        let path = RepoPath::file("foo").unwrap();
+        let data = DataEntry {
+            node: NULL_HASH,
+            delta_base: NULL_HASH,
+            delta: Delta::new_fulltext(b"hello".to_vec()),
+        };
+
+        let parts_in = vec![
+            Part::HistoryMeta {
+                path: path.clone(),
+                entry_count: 0,
+            },
+            Part::DataMeta {
+                path: path.clone(),
+                entry_count: 1,
+            },
+            Part::Data(data.clone()),
+            Part::End,
+        ];
+
+        let mut packer = WirePackPacker::new_with_version(Kind::File, WirePackVersion::V2);
+        let mut buf = BytesMut::new();
+        for part in &parts_in {
+            packer.encode(part, &mut buf).unwrap();
+        }
+
+        let mut unpacker = WirePackUnpacker::new(Kind::File);
+        let parts_out = drain_all(&mut unpacker, &mut buf);
+
+        assert_eq!(parts_out, parts_in);
+        assert_eq!(unpacker.version(), WirePackVersion::V2);
+    }
+}